@@ -0,0 +1,78 @@
+use bincode;
+use chrono::{DateTime, Utc};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::ser::Serialize;
+use serde_cbor;
+
+use store::StoreError;
+
+/// A pluggable on-disk encoding for values stored in a `Store`.
+///
+/// `Store` is generic over `Codec` so the serialization format can be
+/// swapped without touching any of the cache or queue APIs. `CborCodec` is
+/// the default.
+pub trait Codec: Clone + Send + Sync + 'static {
+    /// Encodes a value into its on-disk representation.
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, StoreError>;
+
+    /// Decodes a value from its on-disk representation.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, StoreError>;
+
+    /// Decodes only the leading `(Option<DateTime<Utc>>, u32)` TTL header of
+    /// an encoded cache item, without paying for a full decode of the
+    /// payload that follows it.
+    ///
+    /// This is what lets `ttl_compaction_filter` cheaply decide whether an
+    /// entry has expired during compaction.
+    fn decode_ttl_header(&self, bytes: &[u8]) -> Result<(Option<DateTime<Utc>>, u32), StoreError>;
+}
+
+/// The default codec, backed by `serde_cbor`.
+///
+/// CBOR is self-describing, so values encoded with it can be inspected and
+/// partially decoded (see `decode_ttl_header`) without knowing their exact
+/// shape up front.
+#[derive(Debug, Clone, Default)]
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, StoreError> {
+        serde_cbor::to_vec(value).map_err(StoreError::SerializeError)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, StoreError> {
+        serde_cbor::from_slice(bytes).map_err(StoreError::DeserializeError)
+    }
+
+    fn decode_ttl_header(&self, bytes: &[u8]) -> Result<(Option<DateTime<Utc>>, u32), StoreError> {
+        #[derive(Deserialize)]
+        struct TtlHeader(Option<DateTime<Utc>>, u32, IgnoredAny);
+        let header: TtlHeader =
+            serde_cbor::from_slice(bytes).map_err(StoreError::DeserializeError)?;
+        Ok((header.0, header.1))
+    }
+}
+
+/// An alternative codec backed by `bincode`.
+///
+/// Bincode values are smaller and faster to encode and decode than CBOR,
+/// at the cost of not being self-describing: unlike `CborCodec`,
+/// `decode_ttl_header` works here simply because bincode reads a value off
+/// the front of a byte slice and ignores whatever payload bytes remain
+/// after it, with no need for an `IgnoredAny`-style placeholder.
+#[derive(Debug, Clone, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, StoreError> {
+        bincode::serialize(value).map_err(StoreError::BincodeError)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, StoreError> {
+        bincode::deserialize(bytes).map_err(StoreError::BincodeError)
+    }
+
+    fn decode_ttl_header(&self, bytes: &[u8]) -> Result<(Option<DateTime<Utc>>, u32), StoreError> {
+        bincode::deserialize(bytes).map_err(StoreError::BincodeError)
+    }
+}
@@ -1,3 +1,4 @@
+extern crate bincode;
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
@@ -7,9 +8,13 @@ extern crate serde;
 extern crate serde_cbor;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(test)]
+extern crate tempdir;
 
+mod codec;
 mod store;
 mod traits;
 
+pub use codec::*;
 pub use store::*;
 pub use traits::*;
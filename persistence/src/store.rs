@@ -1,12 +1,20 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
+use bincode;
 use chrono::{DateTime, Duration, Utc};
-use rocksdb::{compaction_filter::Decision, ColumnFamilyDescriptor, Error as RocksDbError, Options,
-              DB as RocksDb};
-use serde::de::IgnoredAny;
+use rocksdb::{compaction_filter::Decision, ColumnFamilyDescriptor, Error as RocksDbError,
+              IteratorMode, OptimisticTransactionDB as RocksDb, Options,
+              Transaction as RocksTransaction};
+use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use serde_cbor;
 
+use codec::{CborCodec, Codec};
 use traits::Cachable;
 
 /// Represents an error from the store.
@@ -27,13 +35,43 @@ pub enum StoreError {
     /// Raised on deserialization errors.
     #[fail(display = "cannot deseralize value from database")]
     DeserializeError(#[cause] serde_cbor::error::Error),
+    /// Raised on serialization errors.
+    #[fail(display = "cannot serialize value for database")]
+    SerializeError(#[cause] serde_cbor::error::Error),
+    /// Raised on bincode serialization or deserialization errors.
+    #[fail(display = "cannot encode or decode value for database")]
+    BincodeError(#[cause] bincode::Error),
+    /// Raised when a transaction could not be committed, whether because
+    /// another transaction modified the same keys first or the commit
+    /// itself failed for some other reason (disk full, I/O error, ...).
+    /// Only the former is actually safe to retry; inspect the wrapped
+    /// error before looping on it.
+    #[fail(display = "cannot commit transaction")]
+    Conflict(#[cause] RocksDbError),
+    /// Raised when reading from or writing to an export stream fails,
+    /// including a stream that ends partway through a length prefix or the
+    /// bytes it announces.
+    #[fail(display = "cannot read or write export stream")]
+    IoError(#[cause] io::Error),
+    /// Raised when an export stream ends between the parts of a record,
+    /// names a column family that is not valid UTF-8 or does not exist, or
+    /// holds a `queue` key that is not 8 bytes long.
+    #[fail(display = "export stream is corrupt")]
+    CorruptExport,
 }
 
 /// Represents the store for the persistence layer.
+///
+/// `Store` is generic over the `Codec` used to encode and decode values;
+/// `open` picks `CborCodec`, the historical default, and `open_with_codec`
+/// lets callers opt into an alternative such as `BincodeCodec`.
 #[derive(Debug)]
-pub struct Store {
+pub struct Store<Cd: Codec = CborCodec> {
     db: RocksDb,
     path: PathBuf,
+    queue_seq: AtomicU64,
+    inflight: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    codec: Cd,
 }
 
 #[derive(Debug, PartialEq)]
@@ -42,23 +80,91 @@ enum FamilyType {
     Cache,
 }
 
-impl Store {
-    /// Opens a store for the given path.
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Store, StoreError> {
-        let path = path.as_ref().to_path_buf();
-        let opts = get_database_options();
-        let cfs = vec![
-            ColumnFamilyDescriptor::new("cache", get_column_family_options(FamilyType::Cache)),
-            ColumnFamilyDescriptor::new("queue", get_column_family_options(FamilyType::Queue)),
-        ];
-        let db = RocksDb::open_cf_descriptors(&opts, &path, cfs).map_err(StoreError::CannotOpen)?;
-        Ok(Store { db, path })
+/// The result of a `cache_get_state` lookup.
+#[derive(Debug)]
+pub enum CacheEntry<C> {
+    /// No usable value is stored for the key.
+    Missing,
+    /// A value is stored and still within its TTL.
+    Fresh(C),
+    /// A value is stored but its TTL has elapsed.
+    Stale(C),
+}
+
+impl<C> CacheEntry<C> {
+    /// Returns the contained value regardless of freshness, if there is one.
+    pub fn into_value(self) -> Option<C> {
+        match self {
+            CacheEntry::Missing => None,
+            CacheEntry::Fresh(value) | CacheEntry::Stale(value) => Some(value),
+        }
+    }
+}
+
+/// Removes a key's entry from `Store::inflight` when dropped, but only if
+/// it is still the same `Arc` this guard was handed.
+///
+/// Used by `cache_get_or_set` to guarantee the entry is cleaned up on every
+/// exit path, including a `compute` failure, not just the success path.
+/// Comparing by identity (rather than unconditionally removing by key)
+/// matters under sustained concurrent traffic on the same key: once this
+/// guard's caller is done, a new caller may already have inserted a fresh
+/// `Arc` for the same key and started its own `compute`, and we must not
+/// rip that one out from under it.
+struct InflightCleanup<'a, Cd: Codec + 'a> {
+    store: &'a Store<Cd>,
+    key: &'a str,
+    lock: Arc<Mutex<()>>,
+}
+
+impl<'a, Cd: Codec> Drop for InflightCleanup<'a, Cd> {
+    fn drop(&mut self) {
+        let mut inflight = self.store.inflight.lock().unwrap();
+        if let Some(current) = inflight.get(self.key) {
+            if Arc::ptr_eq(current, &self.lock) {
+                inflight.remove(self.key);
+            }
+        }
+    }
+}
+
+impl Store<CborCodec> {
+    /// Opens a store for the given path using the default CBOR codec.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Store<CborCodec>, StoreError> {
+        Store::open_with_codec(path, CborCodec)
     }
 
     /// Attempts to repair the store.
     pub fn repair<P: AsRef<Path>>(path: P) -> Result<(), StoreError> {
         RocksDb::repair(get_database_options(), path).map_err(StoreError::RepairFailed)
     }
+}
+
+impl<Cd: Codec> Store<Cd> {
+    /// Opens a store for the given path using a specific codec.
+    pub fn open_with_codec<P: AsRef<Path>>(path: P, codec: Cd) -> Result<Store<Cd>, StoreError> {
+        let path = path.as_ref().to_path_buf();
+        let opts = get_database_options();
+        let cfs = vec![
+            ColumnFamilyDescriptor::new(
+                "cache",
+                get_column_family_options(FamilyType::Cache, codec.clone()),
+            ),
+            ColumnFamilyDescriptor::new(
+                "queue",
+                get_column_family_options(FamilyType::Queue, codec.clone()),
+            ),
+        ];
+        let db = RocksDb::open_cf_descriptors(&opts, &path, cfs).map_err(StoreError::CannotOpen)?;
+        let queue_seq = recover_queue_seq(&db)?;
+        Ok(Store {
+            db,
+            path,
+            queue_seq: AtomicU64::new(queue_seq),
+            inflight: Mutex::new(HashMap::new()),
+            codec,
+        })
+    }
 
     /// Returns the path of the store.
     pub fn path(&self) -> &Path {
@@ -74,16 +180,13 @@ impl Store {
     ) -> Result<(), StoreError> {
         #[derive(Serialize)]
         pub struct CacheItem<'a, T: Serialize + 'a>(Option<DateTime<Utc>>, u32, &'a T);
+        let encoded = self.codec.encode(&CacheItem(
+            ttl.map(|x| Utc::now() + x),
+            C::cache_version(),
+            value,
+        ))?;
         self.db
-            .put_cf(
-                self.db.cf_handle("cache").unwrap(),
-                key.as_bytes(),
-                &serde_cbor::to_vec(&CacheItem(
-                    ttl.map(|x| Utc::now() + x),
-                    C::cache_version(),
-                    value,
-                )).unwrap(),
-            )
+            .put_cf(self.db.cf_handle("cache").unwrap(), key.as_bytes(), &encoded)
             .map_err(StoreError::WriteError)
     }
 
@@ -102,8 +205,7 @@ impl Store {
             .get_cf(self.db.cf_handle("cache").unwrap(), key.as_bytes())
         {
             Ok(Some(value)) => {
-                let item: CacheItem<C> =
-                    serde_cbor::from_slice(&value).map_err(StoreError::DeserializeError)?;
+                let item: CacheItem<C> = self.codec.decode(&value)?;
                 if item.1 != C::cache_version() {
                     return Ok(None);
                 }
@@ -118,6 +220,34 @@ impl Store {
         }
     }
 
+    /// Looks up a value in the cache, distinguishing a missing entry from
+    /// one that is present but past its TTL.
+    ///
+    /// Unlike `cache_get`, a `cache_version` mismatch is still treated as
+    /// `Missing`, but an expired entry is returned as `Stale` instead of
+    /// being collapsed into `None`.
+    pub fn cache_get_state<C: Cachable>(&self, key: &str) -> Result<CacheEntry<C>, StoreError> {
+        #[derive(Deserialize)]
+        pub struct CacheItem<T>(Option<DateTime<Utc>>, u32, T);
+        match self.db
+            .get_cf(self.db.cf_handle("cache").unwrap(), key.as_bytes())
+        {
+            Ok(Some(value)) => {
+                let item: CacheItem<C> = self.codec.decode(&value)?;
+                if item.1 != C::cache_version() {
+                    return Ok(CacheEntry::Missing);
+                }
+                match item.0 {
+                    None => Ok(CacheEntry::Fresh(item.2)),
+                    Some(ts) if ts > Utc::now() => Ok(CacheEntry::Fresh(item.2)),
+                    _ => Ok(CacheEntry::Stale(item.2)),
+                }
+            }
+            Ok(None) => Ok(CacheEntry::Missing),
+            Err(err) => Err(StoreError::ReadError(err)),
+        }
+    }
+
     /// Looks up a value in the cache pruning invalid items.
     ///
     /// This is similar to `cache_get` but in case the value coming back from the cache
@@ -125,36 +255,407 @@ impl Store {
     /// returned instead of producing an error.
     pub fn cache_get_safe<D: Cachable>(&self, key: &str) -> Result<Option<D>, StoreError> {
         self.cache_get(key).or_else(|err| match err {
-            StoreError::DeserializeError(..) => {
+            StoreError::DeserializeError(..) | StoreError::BincodeError(..) => {
                 self.cache_remove(key).ok();
                 Ok(None)
             }
             err => Err(err),
         })
     }
-}
 
-fn ttl_compaction_filter(_level: u32, _key: &[u8], value: &[u8]) -> Decision {
-    #[derive(Deserialize)]
-    pub struct TtlInfo(Option<DateTime<Utc>>, u32, IgnoredAny);
+    /// Looks up a value in the cache, computing and storing it on a miss.
+    ///
+    /// If many callers request the same missing key at once, only one of
+    /// them runs `compute`; the others block until it is done and then read
+    /// the value it stored, instead of all recomputing it at once.
+    pub fn cache_get_or_set<C, F, E>(
+        &self,
+        key: &str,
+        ttl: Option<Duration>,
+        compute: F,
+    ) -> Result<C, E>
+    where
+        C: Cachable,
+        F: FnOnce() -> Result<C, E>,
+        E: From<StoreError>,
+    {
+        if let Some(value) = self.cache_get(key)? {
+            return Ok(value);
+        }
 
-    serde_cbor::from_slice::<TtlInfo>(value)
-        .ok()
-        .and_then(|x| x.0)
-        .map_or(Decision::Keep, |value| {
-            if value < Utc::now() {
-                Decision::Remove
-            } else {
-                Decision::Keep
+        let lock = {
+            let mut inflight = self.inflight.lock().unwrap();
+            inflight
+                .entry(key.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(())))
+                .clone()
+        };
+        let _guard = lock.lock().unwrap();
+        // Removes this key's inflight entry when we return, whether that is
+        // because `compute` succeeded, failed, or another caller already
+        // populated the cache for us; without this an ever-failing compute
+        // would leak an entry for its key forever.
+        let _cleanup = InflightCleanup {
+            store: self,
+            key,
+            lock: lock.clone(),
+        };
+
+        // Another caller may have populated the key while we were waiting
+        // for the lock above.
+        if let Some(value) = self.cache_get(key)? {
+            return Ok(value);
+        }
+
+        let value = compute()?;
+        self.cache_set(key, &value, ttl)?;
+        Ok(value)
+    }
+
+    /// Looks up a value in the cache, serving a stale value immediately
+    /// while refreshing it in the background.
+    ///
+    /// Returns `None` only if there is no value cached at all; in that case
+    /// the caller is expected to compute and store one itself, as with a
+    /// plain cache miss. If the cached value is stale, it is returned right
+    /// away and `compute` is run on a background thread to repopulate the
+    /// key, so a slow recomputation never blocks the caller.
+    ///
+    /// Refreshes go through the same `inflight` map as `cache_get_or_set`,
+    /// so if a key is already being refreshed, concurrent callers just get
+    /// the stale value without spawning another redundant recomputation.
+    pub fn cache_get_refreshing<C, F>(
+        self: &Arc<Self>,
+        key: &str,
+        ttl: Option<Duration>,
+        compute: F,
+    ) -> Result<Option<C>, StoreError>
+    where
+        C: Cachable + Send + 'static,
+        F: FnOnce() -> Result<C, StoreError> + Send + 'static,
+    {
+        match self.cache_get_state(key)? {
+            CacheEntry::Missing => Ok(None),
+            CacheEntry::Fresh(value) => Ok(Some(value)),
+            CacheEntry::Stale(value) => {
+                // Only refresh if no other caller is already computing this
+                // key. The inflight entry inserted here stays in place until
+                // the spawned thread's cleanup removes it, so a second
+                // concurrent caller finds it occupied instead of racing us
+                // to start its own refresh.
+                let lock = {
+                    let mut inflight = self.inflight.lock().unwrap();
+                    if inflight.contains_key(key) {
+                        return Ok(Some(value));
+                    }
+                    let lock = Arc::new(Mutex::new(()));
+                    inflight.insert(key.to_string(), lock.clone());
+                    lock
+                };
+                let store = self.clone();
+                let key = key.to_string();
+                thread::spawn(move || {
+                    let _guard = lock.lock().unwrap();
+                    let _cleanup = InflightCleanup {
+                        store: &*store,
+                        key: &key,
+                        lock: lock.clone(),
+                    };
+                    if let Ok(fresh) = compute() {
+                        store.cache_set(&key, &fresh, ttl).ok();
+                    }
+                });
+                Ok(Some(value))
             }
-        })
+        }
+    }
+
+    /// Pushes a value onto the back of the persistent queue.
+    ///
+    /// Items are read back out in the order they were pushed by `queue_pop`
+    /// and `queue_peek`.
+    pub fn queue_push<V: Serialize>(&self, value: &V) -> Result<(), StoreError> {
+        let seq = self.queue_seq.fetch_add(1, Ordering::SeqCst);
+        let encoded = self.codec.encode(value)?;
+        self.db
+            .put_cf(self.db.cf_handle("queue").unwrap(), &encode_seq(seq), &encoded)
+            .map_err(StoreError::WriteError)
+    }
+
+    /// Removes and returns the oldest value from the persistent queue.
+    pub fn queue_pop<V: DeserializeOwned>(&self) -> Result<Option<V>, StoreError> {
+        let cf = self.db.cf_handle("queue").unwrap();
+        let mut iter = self.db.iterator_cf(cf, IteratorMode::Start);
+        match iter.next() {
+            Some((key, value)) => {
+                let item = self.codec.decode(&value)?;
+                self.db.delete_cf(cf, &key).map_err(StoreError::WriteError)?;
+                Ok(Some(item))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the oldest value of the persistent queue without removing it.
+    pub fn queue_peek<V: DeserializeOwned>(&self) -> Result<Option<V>, StoreError> {
+        let cf = self.db.cf_handle("queue").unwrap();
+        let mut iter = self.db.iterator_cf(cf, IteratorMode::Start);
+        match iter.next() {
+            Some((_key, value)) => {
+                let item = self.codec.decode(&value)?;
+                Ok(Some(item))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the number of items currently sitting in the persistent queue.
+    pub fn queue_len(&self) -> Result<u64, StoreError> {
+        let cf = self.db.cf_handle("queue").unwrap();
+        let iter = self.db.iterator_cf(cf, IteratorMode::Start);
+        Ok(iter.count() as u64)
+    }
+
+    /// Starts a new atomic transaction.
+    ///
+    /// The returned `StoreTransaction` exposes the same cache and queue
+    /// operations as `Store` itself, but none of them become visible to
+    /// other readers until `commit` is called. If another transaction
+    /// commits a conflicting write first, `commit` fails with
+    /// `StoreError::Conflict` carrying the underlying RocksDB error; the
+    /// caller should only retry once it has confirmed that error actually
+    /// indicates a write conflict rather than a permanent failure.
+    pub fn transaction(&self) -> StoreTransaction<Cd> {
+        StoreTransaction {
+            txn: self.db.transaction(),
+            store: self,
+        }
+    }
+
+    /// Writes every column family's key/value pairs into `out` as a single
+    /// self-describing stream.
+    ///
+    /// The dump is taken from a RocksDB snapshot, so it reflects a single
+    /// consistent point in time even while other threads keep writing. The
+    /// records are copied as their raw encoded bytes, so `export`/`import`
+    /// work the same way regardless of which `Codec` the store uses.
+    /// Pass the result to `import` to restore it, back it up, or move it to
+    /// another store.
+    pub fn export<W: Write>(&self, out: W) -> Result<(), StoreError> {
+        let mut writer = BufWriter::new(out);
+        let snapshot = self.db.snapshot();
+        for family in &["cache", "queue"] {
+            let cf = self.db.cf_handle(family).unwrap();
+            let iter = snapshot.iterator_cf(cf, IteratorMode::Start);
+            for (key, value) in iter {
+                write_record(&mut writer, family.as_bytes(), &key, &value)
+                    .map_err(StoreError::IoError)?;
+            }
+        }
+        writer.flush().map_err(StoreError::IoError)
+    }
+
+    /// Restores key/value pairs previously written by `export`.
+    ///
+    /// Imported `queue` entries can carry sequence numbers ahead of this
+    /// store's live counter (the normal case when restoring or migrating a
+    /// busier store), so `queue_seq` is bumped past the highest imported
+    /// queue key once the stream is drained. Without that, the next
+    /// `queue_push` could reuse an imported key and silently clobber it, or
+    /// insert an item that jumps the FIFO order.
+    pub fn import<R: Read>(&self, input: R) -> Result<(), StoreError> {
+        let mut reader = BufReader::new(input);
+        let mut max_queue_seq = None;
+        loop {
+            let family = match read_record(&mut reader).map_err(StoreError::IoError)? {
+                Some(bytes) => bytes,
+                None => break,
+            };
+            let family = String::from_utf8(family).map_err(|_| StoreError::CorruptExport)?;
+            let key = read_record(&mut reader)
+                .map_err(StoreError::IoError)?
+                .ok_or(StoreError::CorruptExport)?;
+            let value = read_record(&mut reader)
+                .map_err(StoreError::IoError)?
+                .ok_or(StoreError::CorruptExport)?;
+            let cf = self.db.cf_handle(&family).ok_or(StoreError::CorruptExport)?;
+            if family == "queue" && key.len() != 8 {
+                // Every real queue key is an 8-byte big-endian sequence
+                // number (see `encode_seq`). Writing anything else through
+                // would not just corrupt the queue; `decode_seq` panics on
+                // a key of the wrong length, which `recover_queue_seq`
+                // calls on the next `Store::open_with_codec`.
+                return Err(StoreError::CorruptExport);
+            }
+            self.db.put_cf(cf, &key, &value).map_err(StoreError::WriteError)?;
+            if family == "queue" {
+                let seq = decode_seq(&key);
+                max_queue_seq = Some(max_queue_seq.map_or(seq, |prev| prev.max(seq)));
+            }
+        }
+        if let Some(max_seq) = max_queue_seq {
+            self.queue_seq.fetch_max(max_seq + 1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
 }
 
-fn get_column_family_options(family: FamilyType) -> Options {
+/// Writes a single length-prefixed `(column family, key, value)` record.
+fn write_record<W: Write>(writer: &mut W, cf: &[u8], key: &[u8], value: &[u8]) -> io::Result<()> {
+    for chunk in &[cf, key, value] {
+        writer.write_all(&(chunk.len() as u32).to_be_bytes())?;
+        writer.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+/// Reads a single length-prefixed record written by `write_record`.
+///
+/// Returns `None` if the stream ends cleanly before the record starts.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < len_buf.len() {
+        match reader.read(&mut len_buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    let mut buf = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+/// A buffered, atomic group of cache and queue operations.
+///
+/// See `Store::transaction` for details.
+pub struct StoreTransaction<'a, Cd: Codec + 'a> {
+    txn: RocksTransaction<'a, RocksDb>,
+    store: &'a Store<Cd>,
+}
+
+impl<'a, Cd: Codec> StoreTransaction<'a, Cd> {
+    /// Caches a certain value as part of this transaction.
+    pub fn cache_set<C: Cachable>(
+        &self,
+        key: &str,
+        value: &C,
+        ttl: Option<Duration>,
+    ) -> Result<(), StoreError> {
+        #[derive(Serialize)]
+        pub struct CacheItem<'b, T: Serialize + 'b>(Option<DateTime<Utc>>, u32, &'b T);
+        let encoded = self.store.codec.encode(&CacheItem(
+            ttl.map(|x| Utc::now() + x),
+            C::cache_version(),
+            value,
+        ))?;
+        self.txn
+            .put_cf(self.store.db.cf_handle("cache").unwrap(), key.as_bytes(), &encoded)
+            .map_err(StoreError::WriteError)
+    }
+
+    /// Removes a key from the cache as part of this transaction.
+    pub fn cache_remove(&self, key: &str) -> Result<(), StoreError> {
+        self.txn
+            .delete_cf(self.store.db.cf_handle("cache").unwrap(), key.as_bytes())
+            .map_err(StoreError::WriteError)
+    }
+
+    /// Pushes a value onto the back of the persistent queue as part of this
+    /// transaction.
+    pub fn queue_push<V: Serialize>(&self, value: &V) -> Result<(), StoreError> {
+        let seq = self.store.queue_seq.fetch_add(1, Ordering::SeqCst);
+        let encoded = self.store.codec.encode(value)?;
+        self.txn
+            .put_cf(self.store.db.cf_handle("queue").unwrap(), &encode_seq(seq), &encoded)
+            .map_err(StoreError::WriteError)
+    }
+
+    /// Removes and returns the oldest value from the persistent queue as
+    /// part of this transaction.
+    ///
+    /// The read is taken with `get_for_update` so that a concurrent
+    /// transaction popping the same item is forced to conflict rather than
+    /// observing (and removing) it twice. The un-locked scan that finds
+    /// candidate keys can race with another, already-committed transaction
+    /// that deleted the smallest key in between, so a miss on one key just
+    /// means that item is gone, not that the queue is empty — keep scanning
+    /// forward instead of returning `None` early.
+    pub fn queue_pop<V: DeserializeOwned>(&self) -> Result<Option<V>, StoreError> {
+        let cf = self.store.db.cf_handle("queue").unwrap();
+        for (key, _) in self.txn.iterator_cf(cf, IteratorMode::Start) {
+            let value = match self.txn
+                .get_for_update_cf(cf, &key, true)
+                .map_err(StoreError::ReadError)?
+            {
+                Some(value) => value,
+                None => continue,
+            };
+            let item = self.store.codec.decode(&value)?;
+            self.txn.delete_cf(cf, &key).map_err(StoreError::WriteError)?;
+            return Ok(Some(item));
+        }
+        Ok(None)
+    }
+
+    /// Commits all operations recorded so far, making them visible
+    /// atomically. Fails with `StoreError::Conflict` if the commit could
+    /// not go through, which includes but is not limited to a concurrent
+    /// transaction touching the same keys first.
+    pub fn commit(self) -> Result<(), StoreError> {
+        self.txn.commit().map_err(StoreError::Conflict)
+    }
+
+    /// Discards all operations recorded so far.
+    pub fn rollback(self) -> Result<(), StoreError> {
+        self.txn.rollback().map_err(StoreError::WriteError)
+    }
+}
+
+/// Encodes a queue sequence number so that lexicographic key order matches
+/// insertion order.
+fn encode_seq(seq: u64) -> [u8; 8] {
+    seq.to_be_bytes()
+}
+
+/// Decodes a queue sequence number previously encoded with `encode_seq`.
+fn decode_seq(key: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(key);
+    u64::from_be_bytes(buf)
+}
+
+/// Recovers the next queue sequence number by seeking to the last key
+/// already stored in the `queue` column family.
+fn recover_queue_seq(db: &RocksDb) -> Result<u64, StoreError> {
+    let cf = db.cf_handle("queue").unwrap();
+    let mut iter = db.iterator_cf(cf, IteratorMode::End);
+    match iter.next() {
+        Some((key, _)) => Ok(decode_seq(&key) + 1),
+        None => Ok(0),
+    }
+}
+
+fn get_column_family_options<Cd: Codec>(family: FamilyType, codec: Cd) -> Options {
     let mut cf_opts = Options::default();
     cf_opts.set_max_write_buffer_number(4);
     if family == FamilyType::Cache {
-        cf_opts.set_compaction_filter("ttl", ttl_compaction_filter);
+        cf_opts.set_compaction_filter("ttl", move |_level: u32, _key: &[u8], value: &[u8]| {
+            codec
+                .decode_ttl_header(value)
+                .ok()
+                .and_then(|(ttl, _)| ttl)
+                .map_or(Decision::Keep, |value| {
+                    if value < Utc::now() {
+                        Decision::Remove
+                    } else {
+                        Decision::Keep
+                    }
+                })
+        });
     }
     cf_opts
 }
@@ -165,3 +666,108 @@ fn get_database_options() -> Options {
     db_opts.create_if_missing(true);
     db_opts
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn open_store() -> (TempDir, Store<CborCodec>) {
+        let dir = TempDir::new("persistence-test").unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        (dir, store)
+    }
+
+    #[test]
+    fn queue_round_trip_preserves_fifo_order() {
+        let (_dir, store) = open_store();
+        store.queue_push(&1u32).unwrap();
+        store.queue_push(&2u32).unwrap();
+        store.queue_push(&3u32).unwrap();
+
+        assert_eq!(store.queue_len().unwrap(), 3);
+        assert_eq!(store.queue_peek::<u32>().unwrap(), Some(1));
+
+        assert_eq!(store.queue_pop::<u32>().unwrap(), Some(1));
+        assert_eq!(store.queue_pop::<u32>().unwrap(), Some(2));
+        assert_eq!(store.queue_pop::<u32>().unwrap(), Some(3));
+        assert_eq!(store.queue_pop::<u32>().unwrap(), None);
+    }
+
+    #[test]
+    fn transaction_rolls_back_without_committing_writes() {
+        let (_dir, store) = open_store();
+        let txn = store.transaction();
+        txn.queue_push(&1u32).unwrap();
+        txn.rollback().unwrap();
+        assert_eq!(store.queue_len().unwrap(), 0);
+    }
+
+    #[test]
+    fn transaction_queue_pop_skips_a_previously_deleted_key() {
+        let (_dir, store) = open_store();
+        store.queue_push(&1u32).unwrap();
+        store.queue_push(&2u32).unwrap();
+
+        // Remove the oldest key with a committed write before the
+        // transaction starts, so its pop must return the next item.
+        let cf = store.db.cf_handle("queue").unwrap();
+        let (oldest_key, _) = store.db.iterator_cf(cf, IteratorMode::Start).next().unwrap();
+        store.db.delete_cf(cf, &oldest_key).unwrap();
+
+        let txn = store.transaction();
+        assert_eq!(txn.queue_pop::<u32>().unwrap(), Some(2));
+        txn.commit().unwrap();
+        assert_eq!(store.queue_len().unwrap(), 0);
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Greeting(String);
+
+    impl Cachable for Greeting {
+        fn cache_version() -> u32 {
+            1
+        }
+    }
+
+    #[test]
+    fn export_then_import_round_trip_preserves_queue_and_cache_state() {
+        let (_src_dir, src) = open_store();
+        src.queue_push(&1u32).unwrap();
+        src.queue_push(&2u32).unwrap();
+        src.cache_set("greeting", &Greeting("hello".to_string()), None)
+            .unwrap();
+
+        let mut buf = Vec::new();
+        src.export(&mut buf).unwrap();
+
+        let (_dst_dir, dst) = open_store();
+        dst.import(buf.as_slice()).unwrap();
+
+        assert_eq!(dst.queue_len().unwrap(), 2);
+        assert_eq!(
+            dst.cache_get::<Greeting>("greeting").unwrap(),
+            Some(Greeting("hello".to_string()))
+        );
+
+        // queue_seq must be bumped past the imported keys, otherwise this
+        // push would reuse sequence number 0 and collide with (or jump
+        // ahead of) what was just imported.
+        dst.queue_push(&3u32).unwrap();
+        assert_eq!(dst.queue_pop::<u32>().unwrap(), Some(1));
+        assert_eq!(dst.queue_pop::<u32>().unwrap(), Some(2));
+        assert_eq!(dst.queue_pop::<u32>().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn import_rejects_a_queue_key_of_the_wrong_length() {
+        let (_dir, store) = open_store();
+        let mut buf = Vec::new();
+        write_record(&mut buf, b"queue", b"short", b"value").unwrap();
+
+        match store.import(buf.as_slice()) {
+            Err(StoreError::CorruptExport) => {}
+            other => panic!("expected CorruptExport, got {:?}", other),
+        }
+    }
+}